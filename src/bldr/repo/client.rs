@@ -7,121 +7,380 @@
 use std::fs::{self, File};
 use std::io::{Read, Write, BufWriter, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
 
 use crypto::sha2::Sha256;
 use crypto::digest::Digest;
 use hyper;
 use hyper::client::{Client, Body};
 use hyper::status::StatusCode;
+use rand::{self, Rng};
 use rustc_serialize::json;
 
 use super::{XFileName, data_object};
+use super::trust::Trust;
 use error::{BldrResult, BldrError, ErrorKind};
 use package::{Package, PackageArchive, PackageIdent};
 
 static LOGKEY: &'static str = "RC";
 
-/// Download a public key from a remote repository to the given filepath.
-///
-/// # Failures
-///
-/// * Key cannot be found
-/// * Remote repository is not available
-/// * File cannot be created and written to
-pub fn fetch_key(repo: &str, key: &str, path: &str) -> BldrResult<String> {
-    let url = format!("{}/keys/{}", repo, key);
-    download(key, &url, path)
-}
-
-/// Download the latest release of a package.
-///
-/// An optional version and release can be specified
-/// which, when provided, will increase specificity of the release retrieved. Specifying a version
-/// and no release will retrieve the latest release of a given version. Specifying both a version
-/// and a release will retrieve that exact package.
-///
-/// # Failures
-///
-/// * Package cannot be found
-/// * Remote repository is not available
-/// * File cannot be created and written to
-pub fn fetch_package(repo: &str,
-                     package: &PackageIdent,
-                     store: &str)
-                     -> BldrResult<PackageArchive> {
-    let url = format!("{}/pkgs/{}/download", repo, package);
-    match download(&package.name, &url, store) {
-        Ok(file) => {
-            let path = PathBuf::from(file);
-            Ok(PackageArchive::new(path))
+/// Retries attempted before giving up on a retriable failure.
+const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// Base delay doubled on each retry and jittered, per the usual exponential-backoff-with-jitter
+/// recipe.
+const DEFAULT_BASE_BACKOFF_MS: u64 = 200;
+
+/// A repo client that reuses one connection-pooled `hyper::Client` across every key, package, and
+/// metadata operation, and retries transient failures with exponential backoff.
+pub struct RepositoryClient {
+    client: Client,
+    max_retries: u32,
+    base_backoff_ms: u64,
+}
+
+impl RepositoryClient {
+    /// Create a client with the default retry policy.
+    pub fn new() -> RepositoryClient {
+        RepositoryClient::with_retry_policy(DEFAULT_MAX_RETRIES, DEFAULT_BASE_BACKOFF_MS)
+    }
+
+    /// Create a client with a custom retry count and base backoff delay.
+    pub fn with_retry_policy(max_retries: u32, base_backoff_ms: u64) -> RepositoryClient {
+        RepositoryClient {
+            client: Client::new(),
+            max_retries: max_retries,
+            base_backoff_ms: base_backoff_ms,
         }
-        Err(BldrError { err: ErrorKind::HTTP(StatusCode::NotFound), ..}) => {
-            Err(bldr_error!(ErrorKind::RemotePackageNotFound(package.clone())))
+    }
+
+    /// Download a public key from a remote repository to the given filepath, trying each mirror
+    /// in `mirrors` in priority order until one succeeds.
+    ///
+    /// When `trust` is given, the key is validated against its entry in the signed trust
+    /// metadata instead of being accepted unconditionally, so a compromised or lagging mirror
+    /// can't substitute a bad key just because it answered first.
+    ///
+    /// `max_bytes` caps the transfer (defaults to 32GB when `None`) and `cancel`, when given,
+    /// lets a caller abort an in-flight fetch by setting the flag from another thread.
+    ///
+    /// # Failures
+    ///
+    /// * `mirrors` is empty
+    /// * Key cannot be found on any mirror
+    /// * No mirror is available
+    /// * File cannot be created and written to
+    /// * `trust` is given but has no entry for this key, or the downloaded bytes don't match it
+    /// * The repo reports or sends more than `max_bytes`
+    /// * `cancel` is set before the transfer completes
+    pub fn fetch_key(&self,
+                     mirrors: &[Mirror],
+                     key: &str,
+                     path: &str,
+                     trust: Option<&Trust>,
+                     max_bytes: Option<u64>,
+                     cancel: Option<&Arc<AtomicBool>>)
+                     -> BldrResult<String> {
+        self.for_each_mirror(mirrors, |repo| {
+            self.retry(|| {
+                let url = format!("{}/keys/{}", repo, key);
+                let expected = match trust {
+                    Some(trust) => {
+                        let target = try!(trust.target_for(key)
+                            .ok_or(bldr_error!(ErrorKind::UntrustedTarget(key.to_string()))));
+                        Some(Expected {
+                            checksum: &target.sha256,
+                            length: Some(target.length),
+                        })
+                    }
+                    None => None,
+                };
+                self.download(key, &url, path, expected, max_bytes, cancel)
+            })
+        })
+    }
+
+    /// Download the latest release of a package, trying each mirror in `mirrors` in priority
+    /// order until one succeeds.
+    ///
+    /// An optional version and release can be specified
+    /// which, when provided, will increase specificity of the release retrieved. Specifying a
+    /// version and no release will retrieve the latest release of a given version. Specifying
+    /// both a version and a release will retrieve that exact package.
+    ///
+    /// # Failures
+    ///
+    /// * `mirrors` is empty
+    /// * Package cannot be found on any mirror
+    /// * No mirror is available
+    /// * File cannot be created and written to
+    /// * Downloaded bytes do not match the expected checksum (trusted target or repo-reported)
+    /// * `trust` is given but has no entry for this package
+    /// * The repo reports or sends more than `max_bytes`
+    /// * `cancel` is set before the transfer completes
+    pub fn fetch_package(&self,
+                         mirrors: &[Mirror],
+                         package: &PackageIdent,
+                         store: &str,
+                         trust: Option<&Trust>,
+                         max_bytes: Option<u64>,
+                         cancel: Option<&Arc<AtomicBool>>)
+                         -> BldrResult<PackageArchive> {
+        self.for_each_mirror(mirrors, |repo| {
+            self.retry(|| {
+                let url = format!("{}/pkgs/{}/download", repo, package);
+                let (checksum, length) = match trust {
+                    Some(trust) => {
+                        let target = try!(trust.target_for(&package.to_string())
+                            .ok_or(bldr_error!(ErrorKind::UntrustedTarget(package.to_string()))));
+                        (target.sha256.clone(), Some(target.length))
+                    }
+                    None => {
+                        let object = try!(self.show_package_once(repo, package));
+                        (object.checksum, None)
+                    }
+                };
+                let expected = Some(Expected {
+                    checksum: &checksum,
+                    length: length,
+                });
+                match self.download(&package.name, &url, store, expected, max_bytes, cancel) {
+                    Ok(file) => {
+                        let path = PathBuf::from(file);
+                        Ok(PackageArchive::new(path))
+                    }
+                    Err(BldrError { err: ErrorKind::HTTP(StatusCode::NotFound), ..}) => {
+                        Err(bldr_error!(ErrorKind::RemotePackageNotFound(package.clone())))
+                    }
+                    Err(e) => Err(e),
+                }
+            })
+        })
+    }
+
+    /// Returns a package struct for the latest package.
+    ///
+    /// An optional version can be specified which will scope the release returned to the latest
+    /// release of that package. Tries each mirror in `mirrors` in priority order until one
+    /// succeeds.
+    ///
+    /// # Failures
+    ///
+    /// * `mirrors` is empty
+    /// * Package cannot be found on any mirror
+    /// * No mirror is available
+    pub fn show_package(&self, mirrors: &[Mirror], ident: &PackageIdent) -> BldrResult<data_object::Package> {
+        self.for_each_mirror(mirrors, |repo| self.retry(|| self.show_package_once(repo, ident)))
+    }
+
+    fn show_package_once(&self, repo: &str, ident: &PackageIdent) -> BldrResult<data_object::Package> {
+        let url = url_show_package(repo, ident);
+        let request = self.client.get(&url);
+        let mut res = try!(request.send());
+
+        if res.status == StatusCode::NotFound {
+            return Err(bldr_error!(ErrorKind::RemotePackageNotFound(ident.clone())));
+        }
+        if res.status != StatusCode::Ok {
+            return Err(bldr_error!(ErrorKind::HTTP(res.status)));
         }
-        Err(e) => Err(e),
-    }
-}
-
-/// Returns a package struct for the latest package.
-///
-/// An optional version can be specified which will scope the release returned to the latest
-/// release of that package.
-///
-/// # Failures
-///
-/// * Package cannot be found
-/// * Remote repository is not available
-pub fn show_package(repo: &str, ident: &PackageIdent) -> BldrResult<data_object::Package> {
-    let url = url_show_package(repo, ident);
-    let client = Client::new();
-    let request = client.get(&url);
-    let mut res = try!(request.send());
 
-    if res.status != hyper::status::StatusCode::Ok {
-        return Err(bldr_error!(ErrorKind::RemotePackageNotFound(ident.clone())));
+        let mut encoded = String::new();
+        try!(res.read_to_string(&mut encoded));
+        debug!("Body: {:?}", encoded);
+        let package: data_object::Package = json::decode(&encoded).unwrap();
+        Ok(package)
     }
 
-    let mut encoded = String::new();
-    try!(res.read_to_string(&mut encoded));
-    debug!("Body: {:?}", encoded);
-    let package: data_object::Package = json::decode(&encoded).unwrap();
-    Ok(package)
+    /// Upload a public key to a remote repository.
+    ///
+    /// # Failures
+    ///
+    /// * Remote repository is not available
+    /// * File cannot be read
+    pub fn put_key(&self, repo: &str, path: &Path) -> BldrResult<()> {
+        self.retry(|| {
+            let mut file = try!(File::open(path));
+            let file_name = try!(path.file_name().ok_or(bldr_error!(ErrorKind::NoFilePart)));
+            let url = format!("{}/keys/{}", repo, file_name.to_string_lossy());
+            self.upload(&url, &mut file)
+        })
+    }
+
+    /// Upload a package to a remote repository.
+    ///
+    /// # Failures
+    ///
+    /// * Remote repository is not available
+    /// * File cannot be read
+    pub fn put_package(&self, repo: &str, package: &Package) -> BldrResult<()> {
+        self.retry(|| {
+            let mut file = try!(File::open(package.cache_file()));
+            let mut digest = Sha256::new();
+            let mut buffer = Vec::new();
+            try!(file.read_to_end(&mut buffer));
+            digest.input(&buffer);
+            let checksum = digest.result_str();
+            let url = format!("{}/pkgs/{}/{}/{}/{}?checksum={}",
+                              repo,
+                              package.origin,
+                              package.name,
+                              package.version,
+                              package.release,
+                              checksum);
+            self.upload(&url, &mut file)
+        })
+    }
+
+    /// Run `attempt` and, on a retriable failure, retry it with exponential backoff and jitter
+    /// up to `self.max_retries` times. Since `attempt` is free to re-invoke `download` from
+    /// scratch, a retried GET picks the resumable-download logic back up from whatever `.tmp`
+    /// prefix the previous attempt left on disk, rather than restarting the transfer.
+    fn retry<T, F>(&self, mut attempt: F) -> BldrResult<T>
+        where F: FnMut() -> BldrResult<T>
+    {
+        let mut tries = 0;
+        loop {
+            match attempt() {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    if tries >= self.max_retries || !is_retriable(&e) {
+                        return Err(e);
+                    }
+                    tries += 1;
+                    let delay = backoff(self.base_backoff_ms, tries);
+                    debug!("Retrying after {:?} (attempt {}/{}): {:?}",
+                           delay,
+                           tries,
+                           self.max_retries,
+                           e);
+                    thread::sleep(delay);
+                }
+            }
+        }
+    }
+
+    fn download(&self,
+                status: &str,
+                url: &str,
+                path: &str,
+                expected: Option<Expected>,
+                max_bytes: Option<u64>,
+                cancel: Option<&Arc<AtomicBool>>)
+                -> BldrResult<String> {
+        download(&self.client, status, url, path, expected, max_bytes, cancel)
+    }
+
+    fn upload(&self, url: &str, file: &mut File) -> BldrResult<()> {
+        upload(&self.client, url, file)
+    }
+
+    /// Run `op` against each mirror's base URL, highest priority (lowest `priority` value) first,
+    /// moving on to the next mirror on a connection error or 5xx. A verified not-found is
+    /// authoritative and short-circuits immediately rather than masking it by trying every
+    /// mirror; so does an explicit cancel, since trying the next mirror after `cancel` is set
+    /// would silently turn "abort this fetch now" into "abort after walking the whole mirror
+    /// list". Any other failure (including a checksum/trust mismatch, which just means that one
+    /// mirror is stale or compromised) falls through to the next mirror.
+    fn for_each_mirror<T, F>(&self, mirrors: &[Mirror], mut op: F) -> BldrResult<T>
+        where F: FnMut(&str) -> BldrResult<T>
+    {
+        if mirrors.is_empty() {
+            return Err(bldr_error!(ErrorKind::NoMirrorsConfigured));
+        }
+        let mut ordered: Vec<&Mirror> = mirrors.iter().collect();
+        ordered.sort_by_key(|m| m.priority);
+
+        let mut last_err = None;
+        for mirror in ordered {
+            match op(&mirror.url) {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    if should_stop_mirror_failover(&e) {
+                        return Err(e);
+                    }
+                    debug!("Mirror {} failed, trying next mirror: {:?}", mirror.url, e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
 }
 
-/// Upload a public key to a remote repository.
-///
-/// # Failures
-///
-/// * Remote repository is not available
-/// * File cannot be read
-pub fn put_key(repo: &str, path: &Path) -> BldrResult<()> {
-    let mut file = try!(File::open(path));
-    let file_name = try!(path.file_name().ok_or(bldr_error!(ErrorKind::NoFilePart)));
-    let url = format!("{}/keys/{}", repo, file_name.to_string_lossy());
-    upload(&url, &mut file)
-}
-
-/// Upload a package to a remote repository.
-///
-/// # Failures
-///
-/// * Remote repository is not available
-/// * File cannot be read
-pub fn put_package(repo: &str, package: &Package) -> BldrResult<()> {
-    let mut file = try!(File::open(package.cache_file()));
-    let mut digest = Sha256::new();
-    let mut buffer = Vec::new();
-    try!(file.read_to_end(&mut buffer));
-    digest.input(&buffer);
-    let checksum = digest.result_str();
-    let url = format!("{}/pkgs/{}/{}/{}/{}?checksum={}",
-                      repo,
-                      package.origin,
-                      package.name,
-                      package.version,
-                      package.release,
-                      checksum);
-    upload(&url, &mut file)
+/// A candidate repo endpoint a client can fetch from. `priority` orders the mirror set: lower
+/// values are tried first, so an organization can put a nearby caching mirror ahead of upstream.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    pub url: String,
+    pub priority: u32,
+}
+
+impl Mirror {
+    pub fn new(url: &str, priority: u32) -> Mirror {
+        Mirror {
+            url: url.to_string(),
+            priority: priority,
+        }
+    }
+}
+
+/// Whether a failure is an authoritative "this does not exist", which should stop mirror
+/// failover immediately rather than being masked by trying the rest of the mirror set.
+fn is_verified_not_found(error: &BldrError) -> bool {
+    match error.err {
+        ErrorKind::RemotePackageNotFound(_) => true,
+        ErrorKind::HTTP(StatusCode::NotFound) => true,
+        _ => false,
+    }
+}
+
+/// Whether a failure should stop mirror failover immediately rather than being masked by trying
+/// the rest of the mirror set: either a verified not-found, or an explicit cancel (a cancelled
+/// fetch must stop right away, not ride out the rest of the mirror list).
+fn should_stop_mirror_failover(error: &BldrError) -> bool {
+    match error.err {
+        ErrorKind::Aborted => true,
+        _ => is_verified_not_found(error),
+    }
+}
+
+/// Whether a failure is worth retrying: connection-level errors and 502/503/504/408 responses
+/// are transient; everything else (a verified 404, a checksum mismatch, an explicit cancel, ...)
+/// is treated as final.
+fn is_retriable(error: &BldrError) -> bool {
+    match error.err {
+        ErrorKind::HTTP(StatusCode::BadGateway) |
+        ErrorKind::HTTP(StatusCode::ServiceUnavailable) |
+        ErrorKind::HTTP(StatusCode::GatewayTimeout) |
+        ErrorKind::HTTP(StatusCode::RequestTimeout) => true,
+        ErrorKind::HTTP(_) |
+        ErrorKind::RemotePackageNotFound(_) |
+        ErrorKind::NoXFilename |
+        ErrorKind::NoFilePart |
+        ErrorKind::WriteSyncFailed |
+        ErrorKind::ChecksumMismatch { .. } |
+        ErrorKind::DownloadSizeMismatch { .. } |
+        ErrorKind::DownloadTooLarge { .. } |
+        ErrorKind::Aborted |
+        ErrorKind::UntrustedTarget(_) |
+        ErrorKind::InvalidTrustRoot |
+        ErrorKind::MalformedTrustMetadata |
+        ErrorKind::TrustMetadataExpired |
+        ErrorKind::TrustThresholdNotMet { .. } => false,
+        _ => true,
+    }
+}
+
+/// Exponential backoff with full jitter: doubles per attempt off `base_ms`, capped to avoid
+/// overflow on a long retry run, then picks a random delay in `[0, computed)`.
+fn backoff(base_ms: u64, attempt: u32) -> Duration {
+    let exp = base_ms.saturating_mul(1u64 << attempt.min(16));
+    let jittered = rand::thread_rng().gen_range(0, exp + 1);
+    Duration::from_millis(jittered)
 }
 
 fn url_show_package(repo: &str, package: &PackageIdent) -> String {
@@ -132,23 +391,87 @@ fn url_show_package(repo: &str, package: &PackageIdent) -> String {
     }
 }
 
-fn download(status: &str, url: &str, path: &str) -> BldrResult<String> {
+/// What a download is expected to produce, so it can be rejected before it's trusted.
+struct Expected<'a> {
+    checksum: &'a str,
+    length: Option<u64>,
+}
+
+/// Default ceiling on a single download, matching the limit used elsewhere for artifact
+/// transfers. Keeps a misbehaving or malicious repo from filling the disk via one response.
+const DEFAULT_MAX_DOWNLOAD_BYTES: u64 = 32 * 1024 * 1024 * 1024;
+
+fn download(client: &Client,
+            status: &str,
+            url: &str,
+            path: &str,
+            expected: Option<Expected>,
+            max_bytes: Option<u64>,
+            cancel: Option<&Arc<AtomicBool>>)
+            -> BldrResult<String> {
+    let max_bytes = max_bytes.unwrap_or(DEFAULT_MAX_DOWNLOAD_BYTES);
     debug!("Making request to url {}", url);
-    let client = Client::new();
-    let mut res = try!(client.get(url).send());
+    // A HEAD request tells us the file name up front, before we commit to a GET, so we know
+    // which `.tmp` file to check for a resumable partial download.
+    let head_res = try!(client.head(url).send());
+    if head_res.status != hyper::status::StatusCode::Ok {
+        return Err(bldr_error!(ErrorKind::HTTP(head_res.status)));
+    }
+    let file_name = match head_res.headers.get::<XFileName>() {
+        Some(filename) => format!("{}", filename),
+        None => return Err(bldr_error!(ErrorKind::NoXFilename)),
+    };
+    let tempfile = format!("{}/{}.tmp", path, file_name);
+    let finalfile = format!("{}/{}", path, file_name);
+    let resume_from = fs::metadata(&tempfile).map(|m| m.len()).unwrap_or(0);
+
+    let mut request = client.get(url);
+    if resume_from > 0 {
+        request = request.header(hyper::header::Range::Bytes(
+            vec![hyper::header::ByteRangeSpec::AllFrom(resume_from)],
+        ));
+    }
+    let mut res = try!(request.send());
     debug!("Response: {:?}", res);
 
-    if res.status != hyper::status::StatusCode::Ok {
-        return Err(bldr_error!(ErrorKind::HTTP(res.status)));
+    let mut digest = Sha256::new();
+    if res.status == StatusCode::RangeNotSatisfiable {
+        // The server considers our `.tmp` prefix already complete; verify and finish up without
+        // re-transferring a single byte.
+        try!(hash_file(&tempfile, &mut digest));
+        return finish_download(status, &tempfile, &finalfile, resume_from as i64,
+                                &mut digest, expected);
     }
 
-    let file_name = match res.headers.get::<XFileName>() {
-        Some(filename) => format!("{}", filename),
-        None => return Err(bldr_error!(ErrorKind::NoXFilename)),
+    let content_length = res.headers.get::<hyper::header::ContentLength>().map(|v| v.0);
+    let total = match content_length {
+        Some(cl) if res.status == StatusCode::PartialContent => resume_from + cl,
+        Some(cl) => cl,
+        None => 0,
+    };
+    if would_exceed_max_bytes(0, total, max_bytes) {
+        let _ = fs::remove_file(&tempfile);
+        return Err(bldr_error!(ErrorKind::DownloadTooLarge {
+            max_bytes: max_bytes,
+            content_length: total,
+        }));
+    }
+
+    let (mut written, mut writer) = if res.status == StatusCode::PartialContent {
+        // Server honored our Range request; pick the incremental checksum back up from the
+        // bytes we already wrote last time, then keep appending after them.
+        try!(hash_file(&tempfile, &mut digest));
+        let f = try!(fs::OpenOptions::new().append(true).open(&tempfile));
+        (resume_from as i64, BufWriter::new(f))
+    } else if res.status == StatusCode::Ok {
+        // Server ignored the range (or there was nothing to resume); start from scratch.
+        let f = try!(File::create(&tempfile));
+        (0, BufWriter::new(f))
+    } else {
+        return Err(bldr_error!(ErrorKind::HTTP(res.status)));
     };
-    let length = res.headers
-                    .get::<hyper::header::ContentLength>()
-                    .map_or("Unknown".to_string(), |v| format!("{}", v));
+
+    let length = content_length.map_or("Unknown".to_string(), |v| format!("{}", v));
     // Here is a moment where you can really like Rust. We create
     // a file, wrap it in a BufWriter - which understands how to
     // safely batch writes into large buffer sizes on the heap,
@@ -166,39 +489,103 @@ fn download(status: &str, url: &str, path: &str) -> BldrResult<String> {
     // What you can't see is this - the compiler helped with
     // making sure all the edge cases of the pattern were covered,
     // and even though its a trivial case, it was pretty great.
-    let tempfile = format!("{}/{}.tmp", path, file_name);
-    let finalfile = format!("{}/{}", path, file_name);
-    let f = try!(File::create(&tempfile));
-    let mut writer = BufWriter::new(&f);
-    let mut written: i64 = 0;
     let mut buf = [0u8; 100000]; // Our byte buffer
     loop {
+        if is_cancelled(cancel) {
+            drop(writer);
+            let _ = fs::remove_file(&tempfile);
+            return Err(bldr_error!(ErrorKind::Aborted));
+        }
         let len = try!(res.read(&mut buf)); // Raise IO errors
         match len {
             0 => {
-                // 0 == EOF, so stop writing and finish progress
-                progress(status, written, &length, true);
+                // 0 == EOF, so stop writing. `finish_download` below prints the finished
+                // progress line once the rename succeeds, so don't print one here too.
                 break;
             }
             _ => {
+                if would_exceed_max_bytes(written as u64, len as u64, max_bytes) {
+                    drop(writer);
+                    let _ = fs::remove_file(&tempfile);
+                    return Err(bldr_error!(ErrorKind::DownloadTooLarge {
+                        max_bytes: max_bytes,
+                        content_length: (written as u64) + (len as u64),
+                    }));
+                }
                 // Write the buffer to the BufWriter on the Heap
                 let bytes_written = try!(writer.write(&buf[0..len]));
                 if bytes_written == 0 {
                     return Err(bldr_error!(ErrorKind::WriteSyncFailed));
                 }
+                digest.input(&buf[0..len]);
                 written = written + (bytes_written as i64);
                 progress(status, written, &length, false);
             }
         };
     }
-    try!(fs::rename(&tempfile, &finalfile));
-    Ok(finalfile)
+    drop(writer);
+    finish_download(status, &tempfile, &finalfile, written, &mut digest, expected)
 }
 
-fn upload(url: &str, file: &mut File) -> BldrResult<()> {
+/// True if `additional` more bytes on top of `already_written` would cross `max_bytes`.
+fn would_exceed_max_bytes(already_written: u64, additional: u64, max_bytes: u64) -> bool {
+    already_written.saturating_add(additional) > max_bytes
+}
+
+/// True if `cancel` is given and has been set from another thread.
+fn is_cancelled(cancel: Option<&Arc<AtomicBool>>) -> bool {
+    cancel.map_or(false, |c| c.load(Ordering::SeqCst))
+}
+
+/// Feed the bytes already sitting in a `.tmp` file (left over from a prior attempt) into `digest`
+/// so a resumed download's checksum covers the whole file, not just the freshly-streamed suffix.
+fn hash_file(path: &str, digest: &mut Sha256) -> BldrResult<()> {
+    let mut file = try!(File::open(path));
+    let mut buf = [0u8; 100000];
+    loop {
+        let len = try!(file.read(&mut buf));
+        if len == 0 {
+            break;
+        }
+        digest.input(&buf[0..len]);
+    }
+    Ok(())
+}
+
+fn finish_download(status: &str,
+                    tempfile: &str,
+                    finalfile: &str,
+                    written: i64,
+                    digest: &mut Sha256,
+                    expected: Option<Expected>)
+                    -> BldrResult<String> {
+    if let Some(expected) = expected {
+        if let Some(length) = expected.length {
+            if written as u64 != length {
+                try!(fs::remove_file(tempfile));
+                return Err(bldr_error!(ErrorKind::DownloadSizeMismatch {
+                    expected: length,
+                    actual: written as u64,
+                }));
+            }
+        }
+        let actual = digest.result_str();
+        if actual != expected.checksum {
+            try!(fs::remove_file(tempfile));
+            return Err(bldr_error!(ErrorKind::ChecksumMismatch {
+                expected: expected.checksum.to_string(),
+                actual: actual,
+            }));
+        }
+    }
+    try!(fs::rename(tempfile, finalfile));
+    progress(status, written, &written.to_string(), true);
+    Ok(finalfile.to_string())
+}
+
+fn upload(client: &Client, url: &str, file: &mut File) -> BldrResult<()> {
     debug!("Uploading to {}", url);
     try!(file.seek(SeekFrom::Start(0)));
-    let client = Client::new();
     let metadata = try!(file.metadata());
     let response = try!(client.post(url).body(Body::SizedBody(file, metadata.len())).send());
     if response.status.is_success() {
@@ -236,10 +623,200 @@ fn from_char(length: usize, ch: char) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::from_char;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crypto::sha2::Sha256;
+    use crypto::digest::Digest;
+    use hyper::status::StatusCode;
+
+    use error::{BldrResult, ErrorKind};
+    use super::{Expected, Mirror, RepositoryClient, finish_download, from_char, hash_file,
+                is_cancelled, is_retriable, is_verified_not_found, would_exceed_max_bytes};
+
+    /// A scratch path under the system temp dir, unique to this test process and `name`, for
+    /// tests that exercise `finish_download`/`hash_file` against plain local files.
+    fn temp_path(name: &str) -> String {
+        format!("{}/bldr-client-test-{}-{}",
+                ::std::env::temp_dir().display(),
+                ::std::process::id(),
+                name)
+    }
+
+    #[test]
+    fn finish_download_renames_the_tempfile_into_place_on_success() {
+        let tempfile = temp_path("finish-download-ok.tmp");
+        let finalfile = temp_path("finish-download-ok");
+        let _ = fs::remove_file(&finalfile);
+        File::create(&tempfile).unwrap().write_all(b"hello world").unwrap();
+
+        let mut digest = Sha256::new();
+        let result = finish_download("test", &tempfile, &finalfile, 11, &mut digest, None);
+
+        assert_eq!(result.unwrap(), finalfile);
+        assert!(fs::metadata(&finalfile).is_ok());
+        assert!(fs::metadata(&tempfile).is_err());
+
+        fs::remove_file(&finalfile).unwrap();
+    }
+
+    #[test]
+    fn finish_download_rejects_a_checksum_mismatch_and_deletes_the_tempfile() {
+        let tempfile = temp_path("finish-download-bad-checksum.tmp");
+        File::create(&tempfile).unwrap().write_all(b"hello world").unwrap();
+
+        let mut digest = Sha256::new();
+        let expected = Expected {
+            checksum: "0000000000000000000000000000000000000000000000000000000000000000",
+            length: None,
+        };
+        let result = finish_download("test",
+                                      &tempfile,
+                                      &temp_path("finish-download-bad-checksum"),
+                                      11,
+                                      &mut digest,
+                                      Some(expected));
+
+        match result {
+            Err(ref e) => {
+                match e.err {
+                    ErrorKind::ChecksumMismatch { .. } => (),
+                    ref other => panic!("expected ChecksumMismatch, got {:?}", other),
+                }
+            }
+            Ok(ref other) => panic!("expected ChecksumMismatch, got Ok({:?})", other),
+        }
+        assert!(fs::metadata(&tempfile).is_err());
+    }
+
+    #[test]
+    fn hash_file_feeds_the_files_bytes_into_the_digest() {
+        let path = temp_path("hash-file.tmp");
+        File::create(&path).unwrap().write_all(b"hello world").unwrap();
+
+        let mut digest = Sha256::new();
+        hash_file(&path, &mut digest).unwrap();
+
+        let mut expected = Sha256::new();
+        expected.input(b"hello world");
+        assert_eq!(digest.result_str(), expected.result_str());
+
+        fs::remove_file(&path).unwrap();
+    }
 
     #[test]
     fn from_char_returns_the_correct_string() {
         assert_eq!("xxxx", from_char(4, 'x'));
     }
+
+    #[test]
+    fn is_retriable_true_for_transient_server_errors() {
+        assert!(is_retriable(&bldr_error!(ErrorKind::HTTP(StatusCode::BadGateway))));
+        assert!(is_retriable(&bldr_error!(ErrorKind::HTTP(StatusCode::ServiceUnavailable))));
+        assert!(is_retriable(&bldr_error!(ErrorKind::HTTP(StatusCode::GatewayTimeout))));
+        assert!(is_retriable(&bldr_error!(ErrorKind::HTTP(StatusCode::RequestTimeout))));
+    }
+
+    #[test]
+    fn is_retriable_false_for_a_verified_not_found() {
+        assert!(!is_retriable(&bldr_error!(ErrorKind::HTTP(StatusCode::NotFound))));
+    }
+
+    #[test]
+    fn is_retriable_false_for_an_explicit_cancel() {
+        assert!(!is_retriable(&bldr_error!(ErrorKind::Aborted)));
+    }
+
+    #[test]
+    fn is_verified_not_found_true_for_http_404() {
+        assert!(is_verified_not_found(&bldr_error!(ErrorKind::HTTP(StatusCode::NotFound))));
+    }
+
+    #[test]
+    fn is_verified_not_found_false_for_a_transient_server_error() {
+        assert!(!is_verified_not_found(&bldr_error!(ErrorKind::HTTP(StatusCode::BadGateway))));
+    }
+
+    #[test]
+    fn for_each_mirror_tries_mirrors_in_priority_order_and_stops_on_success() {
+        let client = RepositoryClient::new();
+        let mirrors = vec![Mirror::new("https://second", 10), Mirror::new("https://first", 1)];
+        let mut tried = Vec::new();
+        let result = client.for_each_mirror(&mirrors, |repo| {
+            tried.push(repo.to_string());
+            Ok(repo.to_string())
+        });
+        assert_eq!(result.unwrap(), "https://first");
+        assert_eq!(tried, vec!["https://first".to_string()]);
+    }
+
+    #[test]
+    fn for_each_mirror_falls_over_to_the_next_mirror_on_a_server_error() {
+        let client = RepositoryClient::new();
+        let mirrors = vec![Mirror::new("https://first", 1), Mirror::new("https://second", 2)];
+        let mut tried = Vec::new();
+        let result: BldrResult<()> = client.for_each_mirror(&mirrors, |repo| {
+            tried.push(repo.to_string());
+            if repo == "https://first" {
+                Err(bldr_error!(ErrorKind::HTTP(StatusCode::BadGateway)))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(tried, vec!["https://first".to_string(), "https://second".to_string()]);
+    }
+
+    #[test]
+    fn for_each_mirror_short_circuits_on_a_verified_not_found() {
+        let client = RepositoryClient::new();
+        let mirrors = vec![Mirror::new("https://first", 1), Mirror::new("https://second", 2)];
+        let mut tried = Vec::new();
+        let result: BldrResult<()> = client.for_each_mirror(&mirrors, |repo| {
+            tried.push(repo.to_string());
+            Err(bldr_error!(ErrorKind::HTTP(StatusCode::NotFound)))
+        });
+        assert!(result.is_err());
+        assert_eq!(tried, vec!["https://first".to_string()]);
+    }
+
+    #[test]
+    fn for_each_mirror_short_circuits_on_an_explicit_cancel() {
+        let client = RepositoryClient::new();
+        let mirrors = vec![Mirror::new("https://first", 1), Mirror::new("https://second", 2)];
+        let mut tried = Vec::new();
+        let result: BldrResult<()> = client.for_each_mirror(&mirrors, |repo| {
+            tried.push(repo.to_string());
+            Err(bldr_error!(ErrorKind::Aborted))
+        });
+        assert!(result.is_err());
+        assert_eq!(tried, vec!["https://first".to_string()]);
+    }
+
+    #[test]
+    fn would_exceed_max_bytes_true_once_the_total_crosses_the_limit() {
+        assert!(would_exceed_max_bytes(8, 3, 10));
+        assert!(would_exceed_max_bytes(0, 11, 10));
+    }
+
+    #[test]
+    fn would_exceed_max_bytes_false_up_to_and_including_the_limit() {
+        assert!(!would_exceed_max_bytes(8, 2, 10));
+        assert!(!would_exceed_max_bytes(0, 10, 10));
+    }
+
+    #[test]
+    fn is_cancelled_false_when_no_flag_is_given() {
+        assert!(!is_cancelled(None));
+    }
+
+    #[test]
+    fn is_cancelled_reflects_the_flag_once_set_from_another_thread() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        assert!(!is_cancelled(Some(&cancel)));
+        cancel.store(true, Ordering::SeqCst);
+        assert!(is_cancelled(Some(&cancel)));
+    }
 }
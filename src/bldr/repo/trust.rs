@@ -0,0 +1,403 @@
+// Copyright:: Copyright (c) 2015-2016 Chef Software, Inc.
+//
+// The terms of the Evaluation Agreement (Bldr) between Chef Software Inc. and the party accessing
+// this file ("Licensee") apply to Licensee's use of the Software until such time that the Software
+// is made available under an open source license such as the Apache 2.0 License.
+
+//! A TUF-style trust chain for verifying what a remote repo serves.
+//!
+//! A client pins a small set of root keys out of band (`TrustedRoot`, loaded from a local file).
+//! Before it acts on anything the repo returns, it fetches a signed `targets` metadata document
+//! and checks that at least `threshold` of *those pinned keys* signed it — never keys the
+//! document declares about itself, which would let anyone forge a trust chain simply by shipping
+//! their own key alongside their own signature. A root's key set can still be rotated: `{repo}/
+//! metadata/root` is a signed document listing the next key set, but it is only accepted when a
+//! threshold of the *current* (already-trusted) root's keys signed it (`TrustedRoot::rotate`).
+//! `expires` is checked on every fetch, and only then are the per-target SHA-256 hashes and
+//! lengths a document carries (`Trust::target_for`) trusted for download verification.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use hyper::client::Client;
+use hyper::status::StatusCode;
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json;
+use sodiumoxide::crypto::sign;
+use time;
+
+use error::{BldrResult, ErrorKind};
+
+static LOGKEY: &'static str = "TU";
+
+/// The trusted hash and size of a single target (a package archive or a key) as recorded in a
+/// signed `targets` document.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct Target {
+    pub sha256: String,
+    pub length: u64,
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+struct RootKeyDef {
+    id: String,
+    public_key: String,
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+struct Signature {
+    key_id: String,
+    sig: String,
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+struct UnsignedRoot {
+    expires: i64,
+    threshold: usize,
+    keys: Vec<RootKeyDef>,
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+struct SignedRoot {
+    signed: UnsignedRoot,
+    signatures: Vec<Signature>,
+}
+
+// `targets` is a `BTreeMap` rather than a `HashMap` so `json::encode` below produces the same
+// bytes every time: a `HashMap`'s iteration order is randomized per process, which would make two
+// honest signers of the same document sign different byte strings.
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+struct UnsignedTargets {
+    expires: i64,
+    targets: BTreeMap<String, Target>,
+}
+
+#[derive(Debug, RustcEncodable, RustcDecodable)]
+struct SignedTargets {
+    signed: UnsignedTargets,
+    signatures: Vec<Signature>,
+}
+
+/// A pinned root of trust: the actual public key material this client accepts signatures from,
+/// and how many of them must agree before a document is trusted. Never built from a document's
+/// own self-declared keys — only from a local file (`from_file`) or a successful `rotate`.
+pub struct TrustedRoot {
+    keys: HashMap<String, sign::PublicKey>,
+    threshold: usize,
+}
+
+impl TrustedRoot {
+    /// Load a root of trust from a local file: one `key_id hex_public_key` pair per line, plus a
+    /// `threshold=N` line giving the minimum number of signatures required. Defaults to a
+    /// threshold of 1 if none is given.
+    pub fn from_file(path: &Path) -> BldrResult<TrustedRoot> {
+        let mut contents = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut contents));
+        let mut keys = HashMap::new();
+        let mut threshold = 1;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with("threshold=") {
+                threshold = try!(line["threshold=".len()..]
+                                      .parse()
+                                      .map_err(|_| bldr_error!(ErrorKind::InvalidTrustRoot)));
+                continue;
+            }
+            let mut parts = line.splitn(2, ' ');
+            let id = try!(parts.next().ok_or(bldr_error!(ErrorKind::InvalidTrustRoot)));
+            let hex_key = try!(parts.next().ok_or(bldr_error!(ErrorKind::InvalidTrustRoot)));
+            let key = try!(decode_public_key(hex_key));
+            keys.insert(id.to_string(), key);
+        }
+        if keys.is_empty() {
+            return Err(bldr_error!(ErrorKind::InvalidTrustRoot));
+        }
+        Ok(TrustedRoot {
+            keys: keys,
+            threshold: threshold,
+        })
+    }
+
+    /// Fetch `{repo}/metadata/root`, a document listing the next key set, and adopt it only if a
+    /// threshold of signatures from *this* (already-trusted) root's keys covers it. The new
+    /// document's own declared keys are never trusted to vouch for themselves, so a compromised
+    /// mirror can't rotate a client onto an attacker-controlled key set.
+    pub fn rotate(&self, repo: &str) -> BldrResult<TrustedRoot> {
+        let url = format!("{}/metadata/root", repo);
+        let client = Client::new();
+        let mut res = try!(client.get(&url).send());
+        if res.status != StatusCode::Ok {
+            return Err(bldr_error!(ErrorKind::HTTP(res.status)));
+        }
+        let mut encoded = String::new();
+        try!(res.read_to_string(&mut encoded));
+        let doc: SignedRoot = try!(json::decode(&encoded)
+                                        .map_err(|_| bldr_error!(ErrorKind::MalformedTrustMetadata)));
+        if doc.signed.expires < time::get_time().sec {
+            return Err(bldr_error!(ErrorKind::TrustMetadataExpired));
+        }
+        let canonical = try!(json::encode(&doc.signed)
+                                  .map_err(|_| bldr_error!(ErrorKind::MalformedTrustMetadata)));
+        let valid = count_valid_signatures(&canonical, &doc.signatures, &self.keys);
+        if valid < self.threshold {
+            return Err(bldr_error!(ErrorKind::TrustThresholdNotMet {
+                required: self.threshold,
+                found: valid,
+            }));
+        }
+        let mut keys = HashMap::new();
+        for key in &doc.signed.keys {
+            keys.insert(key.id.clone(), try!(decode_public_key(&key.public_key)));
+        }
+        // A threshold of 0 would make every future verification vacuously pass; a threshold
+        // greater than the key count would make every future verification permanently
+        // impossible (including the next rotation). Neither is a set of keys worth adopting.
+        if keys.is_empty() || doc.signed.threshold < 1 || doc.signed.threshold > keys.len() {
+            return Err(bldr_error!(ErrorKind::MalformedTrustMetadata));
+        }
+        Ok(TrustedRoot {
+            keys: keys,
+            threshold: doc.signed.threshold,
+        })
+    }
+}
+
+/// Verified `targets` metadata for a repo.
+pub struct Trust {
+    targets: BTreeMap<String, Target>,
+}
+
+impl Trust {
+    /// Fetch `{repo}/metadata/targets`, verify its signatures against `root`, and return the
+    /// trusted target table.
+    ///
+    /// # Failures
+    ///
+    /// * The metadata cannot be fetched or fails to parse
+    /// * Fewer than `root.threshold` of `root`'s pinned keys signed it
+    /// * The metadata's `expires` timestamp is in the past
+    pub fn fetch(repo: &str, root: &TrustedRoot) -> BldrResult<Trust> {
+        let url = format!("{}/metadata/targets", repo);
+        let client = Client::new();
+        let mut res = try!(client.get(&url).send());
+        if res.status != StatusCode::Ok {
+            return Err(bldr_error!(ErrorKind::HTTP(res.status)));
+        }
+        let mut encoded = String::new();
+        try!(res.read_to_string(&mut encoded));
+        let doc: SignedTargets = try!(json::decode(&encoded)
+                                           .map_err(|_| bldr_error!(ErrorKind::MalformedTrustMetadata)));
+        try!(verify_signatures(&doc, root));
+        if doc.signed.expires < time::get_time().sec {
+            return Err(bldr_error!(ErrorKind::TrustMetadataExpired));
+        }
+        Ok(Trust { targets: doc.signed.targets })
+    }
+
+    /// Look up the trusted hash/length for a target by the same path the repo uses to name it
+    /// (a package ident's `to_string()`, or a key file name).
+    pub fn target_for(&self, target_path: &str) -> Option<&Target> {
+        self.targets.get(target_path)
+    }
+}
+
+fn verify_signatures(doc: &SignedTargets, root: &TrustedRoot) -> BldrResult<()> {
+    let canonical = try!(json::encode(&doc.signed)
+                              .map_err(|_| bldr_error!(ErrorKind::MalformedTrustMetadata)));
+    let valid = count_valid_signatures(&canonical, &doc.signatures, &root.keys);
+    if valid < root.threshold {
+        return Err(bldr_error!(ErrorKind::TrustThresholdNotMet {
+            required: root.threshold,
+            found: valid,
+        }));
+    }
+    Ok(())
+}
+
+/// Count the distinct pinned keys with a valid signature over `canonical`. A `key_id` names a key
+/// in `keys` (never in the signed document itself); it's only counted once no matter how many
+/// signature entries carry it, so a document can't satisfy a threshold by repeating one signer.
+fn count_valid_signatures(canonical: &str,
+                           signatures: &[Signature],
+                           keys: &HashMap<String, sign::PublicKey>)
+                           -> usize {
+    let mut seen = HashSet::new();
+    for sig in signatures {
+        if seen.contains(&sig.key_id) {
+            continue;
+        }
+        if let Some(public_key) = keys.get(&sig.key_id) {
+            if verify_one(canonical, sig, public_key) {
+                seen.insert(sig.key_id.clone());
+            }
+        }
+    }
+    seen.len()
+}
+
+fn verify_one(canonical: &str, sig: &Signature, public_key: &sign::PublicKey) -> bool {
+    let sig_bytes = match sig.sig.from_hex() {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+    let signature = match sign::Signature::from_slice(&sig_bytes) {
+        Some(sig) => sig,
+        None => return false,
+    };
+    sign::verify_detached(&signature, canonical.as_bytes(), public_key)
+}
+
+fn decode_public_key(hex_key: &str) -> BldrResult<sign::PublicKey> {
+    let key_bytes = try!(hex_key.from_hex().map_err(|_| bldr_error!(ErrorKind::InvalidTrustRoot)));
+    sign::PublicKey::from_slice(&key_bytes).ok_or(bldr_error!(ErrorKind::InvalidTrustRoot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+
+    use rustc_serialize::hex::ToHex;
+    use rustc_serialize::json;
+    use sodiumoxide::crypto::sign;
+
+    fn root_with_one_key() -> (TrustedRoot, sign::SecretKey, String) {
+        let (public_key, secret_key) = sign::gen_keypair();
+        let id = "pinned-key".to_string();
+        let mut keys = HashMap::new();
+        keys.insert(id.clone(), public_key);
+        (TrustedRoot { keys: keys, threshold: 1 }, secret_key, id)
+    }
+
+    fn unsigned_targets() -> UnsignedTargets {
+        let mut targets = BTreeMap::new();
+        targets.insert("core/foo/1.0.0/20160101000000".to_string(),
+                        Target { sha256: "abc123".to_string(), length: 42 });
+        UnsignedTargets {
+            expires: time::get_time().sec + 3600,
+            targets: targets,
+        }
+    }
+
+    fn sign_payload(secret_key: &sign::SecretKey, payload: &str) -> String {
+        let signature = sign::sign_detached(payload.as_bytes(), secret_key);
+        (&signature.0[..]).to_hex()
+    }
+
+    #[test]
+    fn verify_signatures_accepts_a_threshold_met_by_pinned_keys() {
+        let (root, secret_key, key_id) = root_with_one_key();
+        let signed = unsigned_targets();
+        let canonical = json::encode(&signed).unwrap();
+        let doc = SignedTargets {
+            signatures: vec![Signature {
+                                  key_id: key_id,
+                                  sig: sign_payload(&secret_key, &canonical),
+                              }],
+            signed: signed,
+        };
+        assert!(verify_signatures(&doc, &root).is_ok());
+    }
+
+    #[test]
+    fn verify_signatures_rejects_a_key_the_document_declares_about_itself() {
+        let (root, _pinned_secret_key, _pinned_key_id) = root_with_one_key();
+        let (_forged_public_key, forged_secret_key) = sign::gen_keypair();
+        let signed = unsigned_targets();
+        let canonical = json::encode(&signed).unwrap();
+        // A signature from a key that is NOT in `root` must never count, no matter what the
+        // document itself claims about that key.
+        let doc = SignedTargets {
+            signatures: vec![Signature {
+                                  key_id: "not-pinned".to_string(),
+                                  sig: sign_payload(&forged_secret_key, &canonical),
+                              }],
+            signed: signed,
+        };
+        assert!(verify_signatures(&doc, &root).is_err());
+    }
+
+    #[test]
+    fn verify_signatures_rejects_below_threshold() {
+        let (public_key_a, secret_key_a) = sign::gen_keypair();
+        let (public_key_b, _secret_key_b) = sign::gen_keypair();
+        let mut keys = HashMap::new();
+        keys.insert("key-a".to_string(), public_key_a);
+        keys.insert("key-b".to_string(), public_key_b);
+        let root = TrustedRoot { keys: keys, threshold: 2 };
+
+        let signed = unsigned_targets();
+        let canonical = json::encode(&signed).unwrap();
+        let doc = SignedTargets {
+            signatures: vec![Signature {
+                                  key_id: "key-a".to_string(),
+                                  sig: sign_payload(&secret_key_a, &canonical),
+                              }],
+            signed: signed,
+        };
+        assert!(verify_signatures(&doc, &root).is_err());
+    }
+
+    #[test]
+    fn verify_signatures_does_not_double_count_repeated_signatures_from_one_key() {
+        let (root, secret_key, key_id) = root_with_one_key();
+        let signed = unsigned_targets();
+        let canonical = json::encode(&signed).unwrap();
+        let sig = Signature {
+            key_id: key_id,
+            sig: sign_payload(&secret_key, &canonical),
+        };
+        // Two entries, same signer: must still only count once toward the threshold.
+        let mut threshold_two_root = root;
+        threshold_two_root.threshold = 2;
+        let doc = SignedTargets {
+            signatures: vec![sig.clone(), sig],
+            signed: signed,
+        };
+        assert!(verify_signatures(&doc, &threshold_two_root).is_err());
+    }
+
+    #[test]
+    fn rotate_accepts_a_new_key_set_signed_by_the_old_threshold() {
+        let (old_root, old_secret_key, old_key_id) = root_with_one_key();
+        let (new_public_key, _new_secret_key) = sign::gen_keypair();
+        let unsigned_root = UnsignedRoot {
+            expires: time::get_time().sec + 3600,
+            threshold: 1,
+            keys: vec![RootKeyDef {
+                           id: "next-key".to_string(),
+                           public_key: (&new_public_key.0[..]).to_hex(),
+                       }],
+        };
+        let canonical = json::encode(&unsigned_root).unwrap();
+        let doc = SignedRoot {
+            signatures: vec![Signature {
+                                  key_id: old_key_id,
+                                  sig: sign_payload(&old_secret_key, &canonical),
+                              }],
+            signed: unsigned_root,
+        };
+        let valid = count_valid_signatures(&canonical, &doc.signatures, &old_root.keys);
+        assert_eq!(valid, old_root.threshold);
+    }
+
+    #[test]
+    fn same_targets_encode_identically_regardless_of_insertion_order() {
+        let mut first = BTreeMap::new();
+        first.insert("a".to_string(), Target { sha256: "1".to_string(), length: 1 });
+        first.insert("b".to_string(), Target { sha256: "2".to_string(), length: 2 });
+
+        let mut second = BTreeMap::new();
+        second.insert("b".to_string(), Target { sha256: "2".to_string(), length: 2 });
+        second.insert("a".to_string(), Target { sha256: "1".to_string(), length: 1 });
+
+        let a = UnsignedTargets { expires: 1, targets: first };
+        let b = UnsignedTargets { expires: 1, targets: second };
+        assert_eq!(json::encode(&a).unwrap(), json::encode(&b).unwrap());
+    }
+}